@@ -1,14 +1,29 @@
 #![feature(let_else)]
 
+use std::collections::HashMap;
+use std::convert::Infallible;
 use std::fs;
+use std::net::SocketAddr;
 use std::path::{PathBuf, Path};
 use std::time;
 
 use eyre::{Result, WrapErr};
 
+use hyper::{Body, Request, Response, Server};
+use hyper::service::{make_service_fn, service_fn};
+
+use prometheus::{
+    Encoder, Histogram, IntCounterVec, IntGauge, TextEncoder,
+    register_histogram, register_int_counter_vec, register_int_gauge,
+};
+
 use structopt::StructOpt;
 
-use tracing::{Level, info, debug};
+use regex::Regex;
+
+use tokio::sync::mpsc;
+
+use tracing::{Level, info, debug, warn};
 use tracing_subscriber::prelude::*;
 
 use twitchchat::{
@@ -26,11 +41,48 @@ struct Opts {
     config: PathBuf,
 }
 
-#[derive(Debug, Clone, PartialEq, serde_derive::Deserialize)]
+// NOTE: scalar fields must all come before `rules` and `user_config` below:
+// `toml::to_string_pretty` (used by `Config::save`) serializes fields in
+// declaration order, and TOML can't have a root scalar after a table or
+// array-of-tables. This also assumes `twitchchat::UserConfig` implements
+// `Serialize`; if a future twitchchat upgrade drops that, `Config::save`
+// will need to serialize a parallel struct instead of deriving it here.
+#[derive(Debug, Clone, serde_derive::Deserialize, serde_derive::Serialize)]
 struct Config {
-    user_config: UserConfig,
     channels: Vec<String>,
     wait_seconds: u64,
+    // fallback token-bucket shape for rules that don't set their own;
+    // defaults to a single token that refills once every `wait_seconds`,
+    // per channel and per rule.
+    #[serde(default)]
+    capacity: Option<f64>,
+    #[serde(default)]
+    refill_per_sec: Option<f64>,
+    // how often to re-issue JOINs for all configured channels, as a defense
+    // against silent channel drops that don't surface as a disconnect.
+    #[serde(default = "default_rejoin_interval_seconds")]
+    rejoin_interval_seconds: u64,
+    // users allowed to issue `!autoplay join`/`!autoplay part` in chat.
+    #[serde(default)]
+    admins: Vec<String>,
+    // shortcut: one `!play`-triggering rule per named emote, for viewers who
+    // spam an emote instead of typing a command.
+    #[serde(default)]
+    trigger_emotes: Vec<String>,
+    #[serde(default)]
+    emote_only_required: bool,
+    // address to serve Prometheus metrics on; metrics are disabled if unset.
+    #[serde(default)]
+    metrics_addr: Option<SocketAddr>,
+    // trigger -> response rules; an empty list falls back to the original
+    // `!play` -> `!play` behaviour.
+    #[serde(default)]
+    rules: Vec<Rule>,
+    user_config: UserConfig,
+}
+
+fn default_rejoin_interval_seconds() -> u64 {
+    300
 }
 
 impl Config {
@@ -42,17 +94,336 @@ impl Config {
             .context("couldn't parse config file")?;
         Ok(config)
     }
+
+    fn rule_capacity(&self, rule: &Rule) -> f64 {
+        rule.capacity.or(self.capacity).unwrap_or(1.0)
+    }
+
+    fn rule_refill_per_sec(&self, rule: &Rule) -> f64 {
+        rule.refill_per_sec.or(self.refill_per_sec).unwrap_or_else(|| {
+            let wait_seconds = rule.wait_seconds.unwrap_or(self.wait_seconds).max(1);
+            1.0 / wait_seconds as f64
+        })
+    }
+
+    // rewrites the whole config file, including the oauth token embedded in
+    // `user_config`, and drops any comments the user had in the file.
+    fn save(&self, p: &Path) -> Result<()> {
+        let contents = toml::to_string_pretty(self)
+            .context("couldn't serialize config")?;
+        fs::write(p, contents)
+            .with_context(|| format!("couldn't write config file {}", p.display()))?;
+        Ok(())
+    }
+}
+
+// sent through App's control channel to join or part channels at runtime,
+// e.g. in response to an admin's chat command.
+#[derive(Debug)]
+enum BotMessage {
+    JoinChannels(Vec<String>),
+    PartChannels(Vec<String>),
+}
+
+#[derive(Debug, Clone, serde_derive::Deserialize, serde_derive::Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+enum Trigger {
+    Exact { text: String },
+    Prefix { text: String },
+    Regex { pattern: String },
+    // matches when the message contains this emote, per the Twitch `emotes`
+    // tag rather than the raw text; with `emote_only_required`, the message
+    // must also carry the `emote-only` tag (other emotes may appear too).
+    Emote { name: String },
+}
+
+#[derive(Debug, Clone, serde_derive::Deserialize, serde_derive::Serialize)]
+struct Rule {
+    trigger: Trigger,
+    // whether this rule fires on messages from users whose name ends in
+    // "bot", and on the bot's own messages respectively. Both default to
+    // false, matching the original behaviour.
+    #[serde(default)]
+    from_bots: bool,
+    #[serde(default)]
+    from_self: bool,
+    #[serde(default)]
+    wait_seconds: Option<u64>,
+    #[serde(default)]
+    capacity: Option<f64>,
+    #[serde(default)]
+    refill_per_sec: Option<f64>,
+    // only meaningful for an `Emote` trigger; falls back to
+    // `Config::emote_only_required` when unset.
+    #[serde(default)]
+    emote_only_required: Option<bool>,
+    // may reference `{channel}`, `{user}`, and `{1}`, `{2}`, ... for the
+    // trigger's regex capture groups (unused by exact/prefix/emote triggers).
+    response: String,
+}
+
+fn default_rules(wait_seconds: u64) -> Vec<Rule> {
+    vec![Rule {
+        trigger: Trigger::Exact { text: "!play".to_owned() },
+        from_bots: false,
+        from_self: false,
+        wait_seconds: Some(wait_seconds),
+        capacity: None,
+        refill_per_sec: None,
+        emote_only_required: None,
+        response: "!play".to_owned(),
+    }]
+}
+
+fn emote_shortcut_rules(config: &Config) -> Vec<Rule> {
+    config.trigger_emotes.iter().map(|name| Rule {
+        trigger: Trigger::Emote { name: name.clone() },
+        from_bots: false,
+        from_self: false,
+        wait_seconds: None,
+        capacity: None,
+        refill_per_sec: None,
+        emote_only_required: Some(config.emote_only_required),
+        response: "!play".to_owned(),
+    }).collect()
+}
+
+enum CompiledTrigger {
+    Exact(String),
+    Prefix(String),
+    Regex(Regex),
+    Emote(String),
+}
+
+struct CompiledRule {
+    trigger: CompiledTrigger,
+    from_bots: bool,
+    from_self: bool,
+    emote_only_required: bool,
+    capacity: f64,
+    refill_per_sec: f64,
+    response: String,
+}
+
+fn compile_rules(config: &Config) -> Result<Vec<CompiledRule>> {
+    let mut rules = if config.rules.is_empty() {
+        default_rules(config.wait_seconds)
+    } else {
+        config.rules.clone()
+    };
+    rules.extend(emote_shortcut_rules(config));
+
+    rules.iter().map(|rule| {
+        let trigger = match &rule.trigger {
+            Trigger::Exact { text } => CompiledTrigger::Exact(text.clone()),
+            Trigger::Prefix { text } => CompiledTrigger::Prefix(text.clone()),
+            Trigger::Regex { pattern } => CompiledTrigger::Regex(
+                Regex::new(pattern)
+                    .with_context(|| format!("invalid rule regex {:?}", pattern))?),
+            Trigger::Emote { name } => CompiledTrigger::Emote(name.clone()),
+        };
+
+        Ok(CompiledRule {
+            trigger,
+            from_bots: rule.from_bots,
+            from_self: rule.from_self,
+            emote_only_required: rule.emote_only_required.unwrap_or(config.emote_only_required),
+            capacity: config.rule_capacity(rule),
+            refill_per_sec: config.rule_refill_per_sec(rule),
+            response: rule.response.clone(),
+        })
+    }).collect()
+}
+
+fn privmsg_is_emote_only(privmsg: &Privmsg) -> bool {
+    privmsg.tags().get("emote-only") == Some("1")
+}
+
+// Twitch's `emotes` tag looks like `emote_id:start-end,start-end/emote_id2:start-end`,
+// giving inclusive *code-point* ranges into the raw (untrimmed) message
+// rather than emote names; slice those ranges back out of the text to
+// compare against configured emote names.
+fn privmsg_emote_texts(privmsg: &Privmsg) -> Vec<String> {
+    let Some(raw) = privmsg.tags().get("emotes") else {
+        return Vec::new();
+    };
+
+    let chars: Vec<char> = privmsg.data().chars().collect();
+
+    raw.split('/')
+        .filter_map(|entry| entry.split_once(':'))
+        .flat_map(|(_, ranges)| ranges.split(','))
+        .filter_map(|range| range.split_once('-'))
+        .filter_map(|(start, end)| {
+            let start: usize = start.parse().ok()?;
+            let end: usize = end.parse().ok()?;
+            chars.get(start..=end).map(|s| s.iter().collect())
+        })
+        .collect()
+}
+
+fn render_response(template: &str, channel: &str, user: &str, captures: &[String]) -> String {
+    let mut out = template.replace("{channel}", channel).replace("{user}", user);
+    for (i, capture) in captures.iter().enumerate() {
+        out = out.replace(&format!("{{{}}}", i + 1), capture);
+    }
+    out
+}
+
+#[derive(Clone)]
+struct Metrics {
+    messages_received: IntCounterVec,
+    plays_sent: IntCounterVec,
+    rate_limited: IntCounterVec,
+    connected_channels: IntGauge,
+    reconnect_backoff: Histogram,
+}
+
+impl Metrics {
+    fn new() -> Result<Self> {
+        Ok(Metrics {
+            messages_received: register_int_counter_vec!(
+                "autoplay_messages_received",
+                "chat messages received, by channel",
+                &["channel"])?,
+            plays_sent: register_int_counter_vec!(
+                "autoplay_plays_sent",
+                "rule responses sent, by channel",
+                &["channel"])?,
+            rate_limited: register_int_counter_vec!(
+                "autoplay_rate_limited",
+                "triggers dropped by the rate limiter, by channel",
+                &["channel"])?,
+            connected_channels: register_int_gauge!(
+                "autoplay_connected_channels",
+                "number of channels currently joined")?,
+            // buckets matched to the 1s-60s exponential backoff range in
+            // `supervise`; the default buckets (0.005s-10s) would dump
+            // almost every observation into +Inf.
+            reconnect_backoff: register_histogram!(
+                "autoplay_reconnect_backoff_seconds",
+                "backoff duration waited before a reconnect attempt",
+                vec![1.0, 2.0, 4.0, 8.0, 16.0, 32.0, 60.0])?,
+        })
+    }
+}
+
+// installed once and reused across `App::run`'s select! loop; re-registering
+// the signal handlers on every iteration would leave a window where a signal
+// delivered between iterations is missed.
+#[cfg(unix)]
+struct TerminateSignal {
+    sigint: tokio::signal::unix::Signal,
+    sigterm: tokio::signal::unix::Signal,
+}
+
+#[cfg(unix)]
+impl TerminateSignal {
+    fn new() -> Self {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        TerminateSignal {
+            sigint: signal(SignalKind::interrupt())
+                .expect("couldn't install SIGINT handler"),
+            sigterm: signal(SignalKind::terminate())
+                .expect("couldn't install SIGTERM handler"),
+        }
+    }
+
+    async fn recv(&mut self) {
+        tokio::select! {
+            _ = self.sigint.recv() => {}
+            _ = self.sigterm.recv() => {}
+        }
+    }
+}
+
+#[cfg(windows)]
+struct TerminateSignal;
+
+#[cfg(windows)]
+impl TerminateSignal {
+    fn new() -> Self {
+        TerminateSignal
+    }
+
+    async fn recv(&mut self) {
+        let _ = tokio::signal::ctrl_c().await;
+    }
+}
+
+async fn serve_metrics(addr: SocketAddr) -> Result<()> {
+    let make_svc = make_service_fn(|_conn| async {
+        Ok::<_, Infallible>(service_fn(|_req: Request<Body>| async {
+            let metric_families = prometheus::gather();
+            let mut buffer = Vec::new();
+            TextEncoder::new().encode(&metric_families, &mut buffer)
+                .expect("encoding metrics to a Vec can't fail");
+            Ok::<_, Infallible>(Response::new(Body::from(buffer)))
+        }))
+    });
+
+    Server::bind(&addr).serve(make_svc).await
+        .context("metrics server failed")?;
+
+    Ok(())
+}
+
+// per-channel token bucket, so one channel's traffic can't suppress !play
+// in every other channel.
+struct RateLimiter {
+    tokens: f64,
+    capacity: f64,
+    refill_per_sec: f64,
+    last_refill: time::Instant,
+}
+
+impl RateLimiter {
+    fn new(capacity: f64, refill_per_sec: f64) -> Self {
+        RateLimiter {
+            tokens: capacity,
+            capacity,
+            refill_per_sec,
+            last_refill: time::Instant::now(),
+        }
+    }
+
+    fn try_consume(&mut self) -> bool {
+        let now = time::Instant::now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.refill_per_sec).min(self.capacity);
+        self.last_refill = now;
+
+        if self.tokens < 1.0 {
+            return false;
+        }
+
+        self.tokens -= 1.0;
+        true
+    }
+}
+
+// how App::run ended, so the supervisor can tell a clean shutdown apart
+// from a disconnect it should reconnect from.
+enum RunExit {
+    Disconnected,
+    Shutdown,
 }
 
 struct App {
     config: Config,
+    config_path: PathBuf,
     runner: AsyncRunner,
-    next_allowed: time::Instant,
+    rules: Vec<CompiledRule>,
+    limiters: HashMap<(usize, String), RateLimiter>,
+    bot_tx: mpsc::Sender<BotMessage>,
+    bot_rx: mpsc::Receiver<BotMessage>,
+    metrics: Metrics,
 }
 
 impl App {
-    #[tracing::instrument(skip(config))]
-    async fn connect(config: Config) -> Result<App> {
+    #[tracing::instrument(skip(config, metrics))]
+    async fn connect(config: Config, config_path: PathBuf, metrics: Metrics) -> Result<App> {
         let connector = connector::tokio::Connector::twitch()?;
         let mut runner =
             AsyncRunner::connect(connector, &config.user_config).await?;
@@ -61,92 +432,234 @@ impl App {
             runner.join(&channel).await?;
             info!(?channel, "joined channel");
         }
+        metrics.connected_channels.set(config.channels.len() as i64);
 
-        let next_allowed = time::Instant::now();
+        let rules = compile_rules(&config)?;
+        let limiters = HashMap::new();
+        let (bot_tx, bot_rx) = mpsc::channel(16);
 
-        Ok(App { config, runner, next_allowed })
+        Ok(App { config, config_path, runner, rules, limiters, bot_tx, bot_rx, metrics })
     }
 
     #[tracing::instrument(skip(self))]
-    async fn run(&mut self) -> Result<()> {
+    async fn run(&mut self) -> Result<RunExit> {
+        let mut rejoin_timer = tokio::time::interval(
+            time::Duration::from_secs(self.config.rejoin_interval_seconds));
+        // the first tick fires immediately; connect() already joined once.
+        rejoin_timer.tick().await;
+        let mut terminate_signal = TerminateSignal::new();
+
         loop {
-            let status = self.runner.next_message().await?;
-            debug!(message = ?status, "message");
+            tokio::select! {
+                status = self.runner.next_message() => {
+                    let status = status?;
+                    debug!(message = ?status, "message");
+
+                    let Status::Message(message) = status else {
+                        return Ok(RunExit::Disconnected);
+                    };
+
+                    self.handle(&message).await?;
+                }
+                _ = rejoin_timer.tick() => {
+                    self.rejoin_all().await?;
+                }
+                Some(message) = self.bot_rx.recv() => {
+                    self.handle_bot_message(message).await?;
+                }
+                _ = terminate_signal.recv() => {
+                    info!("received shutdown signal");
+                    self.shutdown().await?;
+                    return Ok(RunExit::Shutdown);
+                }
+            }
+        }
+    }
 
-            let Status::Message(message) = status else {
-                break;
-            };
+    #[tracing::instrument(skip(self))]
+    async fn shutdown(&mut self) -> Result<()> {
+        for channel in &self.config.channels {
+            let mut w = self.runner.writer();
+            if let Err(err) = w.encode(commands::part(channel)).await {
+                warn!(?channel, ?err, "couldn't part channel during shutdown");
+            }
+        }
+
+        let mut w = self.runner.writer();
+        w.flush().await?;
 
-            self.handle(&message).await?;
+        self.runner.quit_handle().notify().await;
+
+        Ok(())
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn rejoin_all(&mut self) -> Result<()> {
+        for channel in &self.config.channels {
+            self.runner.join(channel).await?;
+            debug!(?channel, "rejoined channel");
         }
 
         Ok(())
     }
 
+    #[tracing::instrument(skip(self))]
+    async fn handle_bot_message(&mut self, message: BotMessage) -> Result<()> {
+        match message {
+            BotMessage::JoinChannels(channels) => {
+                for channel in channels {
+                    self.runner.join(&channel).await?;
+                    info!(?channel, "joined channel");
+                    if !self.config.channels.iter().any(|c| *c == channel) {
+                        self.config.channels.push(channel);
+                    }
+                }
+            }
+            BotMessage::PartChannels(channels) => {
+                for channel in channels {
+                    self.runner.part(&channel).await?;
+                    info!(?channel, "parted channel");
+                    self.config.channels.retain(|c| *c != channel);
+                }
+            }
+        }
+
+        self.metrics.connected_channels.set(self.config.channels.len() as i64);
+        self.config.save(&self.config_path)?;
+
+        Ok(())
+    }
+
     #[tracing::instrument(skip(self), level = "debug")]
     async fn handle(&mut self, message: &Commands<'_>) -> Result<()> {
         let Commands::Privmsg(privmsg) = message else {
             return Ok(());
         };
 
-        if !self.is_interesting(&privmsg) {
+        self.metrics.messages_received.with_label_values(&[privmsg.channel()]).inc();
+
+        if let Some(command) = self.admin_command(&privmsg) {
+            // we hold both ends, so this can only fail if the channel is
+            // full; drop the command rather than block the message loop.
+            let _ = self.bot_tx.try_send(command);
             return Ok(());
         }
 
-        if self.dont_spam() {
+        let Some((rule_index, response)) = self.matching_rule(&privmsg) else {
+            return Ok(());
+        };
+
+        if self.dont_spam(rule_index, privmsg.channel()) {
             return Ok(())
         }
 
-        self.say_play(privmsg.channel()).await?;
+        self.say(privmsg.channel(), &response).await?;
 
         Ok(())
     }
 
     #[tracing::instrument(skip(self, privmsg), level = "debug")]
-    fn is_interesting(&self, privmsg: &Privmsg) -> bool {
-        if privmsg.name() == self.runner.identity.username() {
-            debug!("ignoring message from self");
-            return false;
+    fn matching_rule(&self, privmsg: &Privmsg) -> Option<(usize, String)> {
+        let is_self = privmsg.name() == self.runner.identity.username();
+        let is_bot = privmsg.name().ends_with("bot");
+        let text = privmsg.data().trim();
+
+        for (index, rule) in self.rules.iter().enumerate() {
+            if is_self && !rule.from_self {
+                debug!("ignoring message from self");
+                continue;
+            }
+
+            if is_bot && !rule.from_bots {
+                // implement half of https://ircbots.github.io/
+                // "Automatic Replies Non-Proliferation Protocol"
+                debug!("ignoring bot");
+                continue;
+            }
+
+            let captures = match &rule.trigger {
+                CompiledTrigger::Exact(value) => {
+                    text.eq_ignore_ascii_case(value).then(Vec::new)
+                }
+                CompiledTrigger::Prefix(value) => {
+                    let matches = text.as_bytes().get(..value.len())
+                        .is_some_and(|b| b.eq_ignore_ascii_case(value.as_bytes()));
+                    matches.then(Vec::new)
+                }
+                CompiledTrigger::Regex(regex) => regex.captures(text).map(|captures| {
+                    captures.iter()
+                        .skip(1)
+                        .map(|m| m.map(|m| m.as_str().to_owned()).unwrap_or_default())
+                        .collect()
+                }),
+                CompiledTrigger::Emote(name) => {
+                    let allowed = !rule.emote_only_required || privmsg_is_emote_only(privmsg);
+                    let matches = allowed
+                        && privmsg_emote_texts(privmsg).iter().any(|e| e == name);
+                    matches.then(Vec::new)
+                }
+            };
+
+            let Some(captures) = captures else {
+                continue;
+            };
+
+            debug!(rule = index, "rule matched");
+            let response =
+                render_response(&rule.response, privmsg.channel(), privmsg.name(), &captures);
+            return Some((index, response));
         }
 
-        if privmsg.name().ends_with("bot") {
-            // implement half of https://ircbots.github.io/
-            // "Automatic Replies Non-Proliferation Protocol"
-            debug!("ignoring bot");
-            return false;
+        debug!("no rule matched");
+        None
+    }
+
+    #[tracing::instrument(skip(self, privmsg), level = "debug")]
+    fn admin_command(&self, privmsg: &Privmsg) -> Option<BotMessage> {
+        if !self.config.admins.iter().any(|admin| admin == privmsg.name()) {
+            return None;
         }
 
-        let text = privmsg.data().trim().to_ascii_lowercase();
-        if text != "!play" {
-            // TODO: figure out which emotes also count
-            // TODO: figure out if it really only counts when !play is
-            // the entire message.
-            debug!("ignoring non-!play message");
-            return false;
+        let text = privmsg.data().trim();
+        let mut words = text.split_whitespace();
+
+        if words.next()? != "!autoplay" {
+            return None;
         }
 
-        return true;
+        let action = words.next()?;
+        let channel = words.next()?.to_owned();
+
+        match action {
+            "join" => Some(BotMessage::JoinChannels(vec![channel])),
+            "part" => Some(BotMessage::PartChannels(vec![channel])),
+            _ => {
+                debug!(?action, "unknown !autoplay subcommand");
+                None
+            }
+        }
     }
 
     #[tracing::instrument(skip(self), level = "debug")]
-    fn dont_spam(&mut self) -> bool {
-        let now = time::Instant::now();
+    fn dont_spam(&mut self, rule_index: usize, channel: &str) -> bool {
+        let rule = &self.rules[rule_index];
+        let limiter = self.limiters.entry((rule_index, channel.to_owned()))
+            .or_insert_with(|| RateLimiter::new(rule.capacity, rule.refill_per_sec));
 
-        if now < self.next_allowed {
+        if !limiter.try_consume() {
             debug!("not allowed yet");
+            self.metrics.rate_limited.with_label_values(&[channel]).inc();
             return true;
         }
 
-        self.next_allowed =
-            now + time::Duration::from_secs(self.config.wait_seconds);
-
         return false;
     }
 
     #[tracing::instrument(skip(self))]
-    async fn say_play(&mut self, channel: &str) -> Result<()> {
+    async fn say(&mut self, channel: &str, text: &str) -> Result<()> {
         let mut w = self.runner.writer();
-        w.encode(commands::privmsg(channel, "!play")).await?;
+        w.encode(commands::privmsg(channel, text)).await?;
+        self.metrics.plays_sent.with_label_values(&[channel]).inc();
         Ok(())
     }
 }
@@ -168,16 +681,65 @@ fn init_tracing() -> Result<()> {
     Ok(())
 }
 
+#[tracing::instrument(skip(config_path, metrics))]
+async fn supervise(config_path: PathBuf, metrics: Metrics) -> Result<()> {
+    let min_backoff = time::Duration::from_secs(1);
+    let max_backoff = time::Duration::from_secs(60);
+    let mut backoff = min_backoff;
+
+    loop {
+        // reload on every (re)connect attempt, so channels joined/parted or
+        // other config edits made while the bot was running take effect.
+        match Config::load(&config_path) {
+            Ok(config) => {
+                match App::connect(config, config_path.clone(), metrics.clone()).await {
+                    Ok(mut app) => {
+                        backoff = min_backoff;
+
+                        match app.run().await {
+                            Ok(RunExit::Shutdown) => {
+                                info!("shut down cleanly");
+                                return Ok(());
+                            }
+                            Ok(RunExit::Disconnected) => {
+                                warn!("disconnected, reconnecting");
+                            }
+                            Err(err) => {
+                                warn!(?err, "disconnected, reconnecting");
+                            }
+                        }
+                    }
+                    Err(err) => {
+                        warn!(?err, "couldn't connect, retrying");
+                    }
+                }
+            }
+            Err(err) => {
+                warn!(?err, "couldn't load config, retrying");
+            }
+        }
+
+        metrics.reconnect_backoff.observe(backoff.as_secs_f64());
+        tokio::time::sleep(backoff).await;
+        backoff = (backoff * 2).min(max_backoff);
+    }
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
     init_tracing().context("couldn't init tracing")?;
 
     let opts = Opts::from_args();
     let config = Config::load(&opts.config)?;
+    let metrics = Metrics::new().context("couldn't register metrics")?;
+
+    if let Some(addr) = config.metrics_addr {
+        tokio::spawn(async move {
+            if let Err(err) = serve_metrics(addr).await {
+                warn!(?err, "metrics server exited");
+            }
+        });
+    }
 
-    let mut app = App::connect(config).await?;
-
-    app.run().await?;
-
-    Ok(())
+    supervise(opts.config, metrics).await
 }